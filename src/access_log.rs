@@ -0,0 +1,316 @@
+use crate::message::{Address, AddressWithPort, Command, SocksReply};
+use anyhow::Context;
+use clap::ValueEnum;
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{stdout, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+use tracing::{error, warn};
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum AccessLogFormat {
+	/// One human readable line per session.
+	Text,
+	/// One JSON object per line and session.
+	Json,
+}
+
+/// Writes one record per finished session, including sessions that failed or were rejected.
+///
+/// The actual writing happens on a dedicated thread so slow I/O doesn't block the runtime.
+/// If that thread can't keep up, records are dropped instead of queueing up without bound.
+pub struct AccessLog {
+	format: AccessLogFormat,
+	sender: SyncSender<String>,
+	thread: JoinHandle<()>,
+	dropped_records: AtomicU64,
+}
+
+/// Maximum number of records waiting to be written.
+const QUEUE_CAPACITY: usize = 1024;
+
+impl AccessLog {
+	/// Appends to the file at `path` or writes to stdout if no path is given.
+	pub fn open(format: AccessLogFormat, path: Option<&Path>) -> anyhow::Result<Self> {
+		let output: Box<dyn Write + Send> = match path {
+			Some(path) => Box::new(
+				OpenOptions::new()
+					.create(true)
+					.append(true)
+					.open(path)
+					.with_context(|| format!("Failed to open access log file {}", path.display()))?,
+			),
+			None => Box::new(stdout()),
+		};
+
+		let (sender, receiver) = sync_channel::<String>(QUEUE_CAPACITY);
+		let thread = thread::Builder::new()
+			.name("access-log".into())
+			.spawn(move || {
+				let mut output = output;
+				for line in receiver {
+					if let Err(error) = output.write_all(line.as_bytes()).and_then(|()| output.flush()) {
+						error!("Failed to write access log: {error}");
+					}
+				}
+			})
+			.context("Failed to spawn access log thread")?;
+
+		Ok(Self {
+			format,
+			sender,
+			thread,
+			dropped_records: AtomicU64::new(0),
+		})
+	}
+
+	pub fn write(&self, record: &AccessLogRecord) {
+		let mut line = match self.format {
+			AccessLogFormat::Text => record.to_text(),
+			AccessLogFormat::Json => record.to_json(),
+		};
+		line.push('\n');
+
+		match self.sender.try_send(line) {
+			Ok(()) => {}
+			Err(TrySendError::Full(_)) => {
+				let dropped_records = self.dropped_records.fetch_add(1, Ordering::Relaxed) + 1;
+				warn!(dropped_records, "Access log can't keep up, dropping record");
+			}
+			Err(TrySendError::Disconnected(_)) => error!("Access log thread is gone, dropping record"),
+		}
+	}
+
+	/// Writes all queued records and waits for the writing thread to finish.
+	pub fn close(self) {
+		let Self {
+			sender,
+			thread,
+			dropped_records,
+			..
+		} = self;
+		drop(sender);
+		if thread.join().is_err() {
+			error!("Access log thread panicked");
+		}
+
+		let dropped_records = dropped_records.into_inner();
+		if dropped_records > 0 {
+			warn!(dropped_records, "Some access log records were dropped");
+		}
+	}
+}
+
+/// Information about a single proxy session, filled in as the session progresses.
+pub struct AccessLogRecord {
+	timestamp: SystemTime,
+	start: Instant,
+	pub client_address: SocketAddr,
+	pub command: Option<Command>,
+	pub destination: Option<(Address, u16)>,
+	pub upstream_address: Option<SocketAddr>,
+	pub reply: Option<SocksReply>,
+	/// Why the session ended early, e.g. a handshake timeout or a rejected method selection.
+	pub error: Option<String>,
+	pub request_bytes: u64,
+	pub response_bytes: u64,
+}
+
+impl AccessLogRecord {
+	pub fn new(client_address: SocketAddr) -> Self {
+		Self {
+			timestamp: SystemTime::now(),
+			start: Instant::now(),
+			client_address,
+			command: None,
+			destination: None,
+			upstream_address: None,
+			reply: None,
+			error: None,
+			request_bytes: 0,
+			response_bytes: 0,
+		}
+	}
+
+	fn unix_timestamp(&self) -> f64 {
+		self.timestamp
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or(Duration::ZERO)
+			.as_secs_f64()
+	}
+
+	fn duration_milliseconds(&self) -> u128 {
+		self.start.elapsed().as_millis()
+	}
+
+	fn to_text(&self) -> String {
+		let mut text = format!("{:.3} client={}", self.unix_timestamp(), self.client_address);
+		if let Some(command) = self.command {
+			let _ = write!(text, " command={command:?}");
+		}
+		if let Some((address, port)) = &self.destination {
+			let _ = write!(text, " destination={}", AddressWithPort(address, *port));
+		}
+		if let Some(upstream_address) = self.upstream_address {
+			let _ = write!(text, " upstream={upstream_address}");
+		}
+		if let Some(reply) = self.reply {
+			let _ = write!(text, " reply={}", u8::from(reply));
+		}
+		if let Some(error) = &self.error {
+			let _ = write!(text, " error={error:?}");
+		}
+		let _ = write!(
+			text,
+			" request_bytes={} response_bytes={} duration_ms={}",
+			self.request_bytes,
+			self.response_bytes,
+			self.duration_milliseconds()
+		);
+		text
+	}
+
+	fn to_json(&self) -> String {
+		let mut json = format!(
+			r#"{{"timestamp":{:.3},"client_address":"{}","#,
+			self.unix_timestamp(),
+			self.client_address
+		);
+
+		json.push_str(r#""command":"#);
+		match self.command {
+			Some(command) => write_json_string(&mut json, &format!("{command:?}")),
+			None => json.push_str("null"),
+		}
+
+		match &self.destination {
+			Some((address, port)) => {
+				json.push_str(r#","destination_address":"#);
+				write_json_string(&mut json, &address.to_string());
+				let _ = write!(json, r#","destination_port":{port}"#);
+			}
+			None => json.push_str(r#","destination_address":null,"destination_port":null"#),
+		}
+
+		match self.upstream_address {
+			Some(upstream_address) => {
+				let _ = write!(json, r#","upstream_address":"{upstream_address}""#);
+			}
+			None => json.push_str(r#","upstream_address":null"#),
+		}
+
+		match self.reply {
+			Some(reply) => {
+				let _ = write!(json, r#","reply":{}"#, u8::from(reply));
+			}
+			None => json.push_str(r#","reply":null"#),
+		}
+
+		json.push_str(r#","error":"#);
+		match &self.error {
+			Some(error) => write_json_string(&mut json, error),
+			None => json.push_str("null"),
+		}
+
+		let _ = write!(
+			json,
+			r#","request_bytes":{},"response_bytes":{},"duration_ms":{}}}"#,
+			self.request_bytes,
+			self.response_bytes,
+			self.duration_milliseconds()
+		);
+		json
+	}
+}
+
+/// Writes `value` as a quoted JSON string, escaping as required by RFC 8259.
+fn write_json_string(json: &mut String, value: &str) {
+	json.push('"');
+	for character in value.chars() {
+		match character {
+			'"' => json.push_str(r#"\""#),
+			'\\' => json.push_str(r"\\"),
+			'\n' => json.push_str(r"\n"),
+			'\r' => json.push_str(r"\r"),
+			'\t' => json.push_str(r"\t"),
+			control if control.is_control() => {
+				let _ = write!(json, r"\u{:04x}", u32::from(control));
+			}
+			character => json.push(character),
+		}
+	}
+	json.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::{Ipv4Addr, Ipv6Addr};
+
+	fn test_record() -> AccessLogRecord {
+		let mut record = AccessLogRecord::new(SocketAddr::from((Ipv4Addr::LOCALHOST, 50000)));
+		record.timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+		record
+	}
+
+	fn record_with_all_fields() -> AccessLogRecord {
+		let mut record = test_record();
+		record.command = Some(Command::Connect);
+		record.destination = Some((Address::DomainName(b"a\"b\\c\nd\x01e\x7f".to_vec()), 443));
+		record.upstream_address = Some(SocketAddr::from((Ipv6Addr::LOCALHOST, 8080)));
+		record.reply = Some(SocksReply::Succeeded);
+		record.error = Some("tab\there".to_owned());
+		record.request_bytes = 12;
+		record.response_bytes = 34;
+		record
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn json_with_all_fields_missing() {
+		let record = test_record();
+		tokio::time::advance(Duration::from_millis(42)).await;
+		assert_eq!(
+			record.to_json(),
+			concat!(
+				r#"{"timestamp":1700000000.500,"client_address":"127.0.0.1:50000","command":null,"#,
+				r#""destination_address":null,"destination_port":null,"upstream_address":null,"reply":null,"#,
+				r#""error":null,"request_bytes":0,"response_bytes":0,"duration_ms":42}"#
+			)
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn json_escapes_strings() {
+		let record = record_with_all_fields();
+		tokio::time::advance(Duration::from_millis(42)).await;
+		assert_eq!(
+			record.to_json(),
+			concat!(
+				r#"{"timestamp":1700000000.500,"client_address":"127.0.0.1:50000","command":"Connect","#,
+				r#""destination_address":"a\"b\\c\nd\u0001e\u007f","destination_port":443,"#,
+				r#""upstream_address":"[::1]:8080","reply":0,"error":"tab\there","#,
+				r#""request_bytes":12,"response_bytes":34,"duration_ms":42}"#
+			)
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn text_with_all_fields() {
+		let mut record = record_with_all_fields();
+		record.destination = Some((Address::Ipv6(Ipv6Addr::LOCALHOST), 443));
+		tokio::time::advance(Duration::from_millis(42)).await;
+		assert_eq!(
+			record.to_text(),
+			concat!(
+				r#"1700000000.500 client=127.0.0.1:50000 command=Connect destination=[::1]:443 upstream=[::1]:8080 "#,
+				r#"reply=0 error="tab\there" request_bytes=12 response_bytes=34 duration_ms=42"#
+			)
+		);
+	}
+}
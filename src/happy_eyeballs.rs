@@ -0,0 +1,261 @@
+//! Connection establishment inspired by Happy Eyeballs, see https://datatracker.ietf.org/doc/html/rfc8305
+
+use crate::outgoing::connect_from;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tracing::{debug, Instrument};
+
+/// Races connection attempts to the given addresses, alternating between IPv4 and IPv6.
+///
+/// A new attempt is started whenever `attempt_delay` passes or the previous attempt failed.
+/// The first successful connection is returned, all other attempts are cancelled.
+pub async fn connect(
+	addresses: Vec<SocketAddr>,
+	attempt_delay: Duration,
+	outgoing_address: Option<IpAddr>,
+) -> tokio::io::Result<TcpStream> {
+	race(addresses, attempt_delay, move |address| {
+		connect_from(address, outgoing_address)
+	})
+	.await
+}
+
+/// Implementation of [`connect`] that is independent of how a single connection attempt is made.
+async fn race<Connection, Attempt, AttemptFuture>(
+	addresses: Vec<SocketAddr>,
+	attempt_delay: Duration,
+	attempt: Attempt,
+) -> tokio::io::Result<Connection>
+where
+	Connection: Send + 'static,
+	Attempt: Fn(SocketAddr) -> AttemptFuture,
+	AttemptFuture: Future<Output = tokio::io::Result<Connection>> + Send + 'static,
+{
+	let mut candidates = interleave_address_families(addresses).into_iter();
+	let mut attempts = JoinSet::new();
+	let mut last_error = None;
+
+	loop {
+		if attempts.is_empty() {
+			match candidates.next() {
+				Some(address) => spawn_attempt(&mut attempts, address, &attempt),
+				None => {
+					return Err(last_error.unwrap_or_else(|| {
+						tokio::io::Error::new(ErrorKind::InvalidInput, "could not resolve to any addresses")
+					}))
+				}
+			}
+		}
+
+		tokio::select! {
+			Some(result) = attempts.join_next() => {
+				match result {
+					Ok((_, Ok(connection))) => return Ok(connection),
+					Ok((address, Err(error))) => {
+						debug!(%address, "Connection attempt failed: {error}");
+						last_error = Some(error);
+						// Start the next attempt right away instead of waiting for the delay
+						if let Some(address) = candidates.next() {
+							spawn_attempt(&mut attempts, address, &attempt);
+						}
+					}
+					Err(join_error) => last_error = Some(tokio::io::Error::new(ErrorKind::Other, join_error)),
+				}
+			}
+			_ = tokio::time::sleep(attempt_delay), if candidates.len() > 0 => {
+				if let Some(address) = candidates.next() {
+					spawn_attempt(&mut attempts, address, &attempt);
+				}
+			}
+		}
+	}
+}
+
+fn spawn_attempt<Connection, Attempt, AttemptFuture>(
+	attempts: &mut JoinSet<(SocketAddr, tokio::io::Result<Connection>)>,
+	address: SocketAddr,
+	attempt: &Attempt,
+) where
+	Connection: Send + 'static,
+	Attempt: Fn(SocketAddr) -> AttemptFuture,
+	AttemptFuture: Future<Output = tokio::io::Result<Connection>> + Send + 'static,
+{
+	debug!(%address, "Starting connection attempt");
+	let attempt = attempt(address);
+	attempts.spawn(async move { (address, attempt.await) }.in_current_span());
+}
+
+/// Reorders the addresses so address families alternate, starting with the family of the first address.
+/// The relative order within each family is preserved.
+fn interleave_address_families(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+	let Some(first_is_ipv6) = addresses.first().map(SocketAddr::is_ipv6) else {
+		return addresses;
+	};
+
+	let (preferred, other): (Vec<_>, Vec<_>) = addresses
+		.into_iter()
+		.partition(|address| address.is_ipv6() == first_is_ipv6);
+
+	let mut preferred = preferred.into_iter();
+	let mut other = other.into_iter();
+	let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+	loop {
+		match (preferred.next(), other.next()) {
+			(None, None) => return interleaved,
+			(first, second) => interleaved.extend(first.into_iter().chain(second)),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::{Ipv4Addr, Ipv6Addr};
+	use std::sync::{Arc, Mutex};
+	use tokio::time::Instant;
+
+	fn ipv4(last_octet: u8) -> SocketAddr {
+		SocketAddr::from((Ipv4Addr::new(192, 0, 2, last_octet), 443))
+	}
+
+	fn ipv6(last_segment: u16) -> SocketAddr {
+		SocketAddr::from((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, last_segment), 443))
+	}
+
+	#[test]
+	fn interleaving_starts_with_ipv6() {
+		let addresses = vec![ipv6(1), ipv6(2), ipv4(1), ipv4(2)];
+		assert_eq!(
+			interleave_address_families(addresses),
+			vec![ipv6(1), ipv4(1), ipv6(2), ipv4(2)]
+		);
+	}
+
+	#[test]
+	fn interleaving_starts_with_ipv4() {
+		let addresses = vec![ipv4(1), ipv4(2), ipv6(1), ipv6(2)];
+		assert_eq!(
+			interleave_address_families(addresses),
+			vec![ipv4(1), ipv6(1), ipv4(2), ipv6(2)]
+		);
+	}
+
+	#[test]
+	fn interleaving_appends_the_rest_of_the_larger_family() {
+		let addresses = vec![ipv6(1), ipv4(1), ipv6(2), ipv6(3)];
+		assert_eq!(
+			interleave_address_families(addresses),
+			vec![ipv6(1), ipv4(1), ipv6(2), ipv6(3)]
+		);
+		let addresses = vec![ipv6(1), ipv4(1), ipv4(2), ipv4(3)];
+		assert_eq!(
+			interleave_address_families(addresses),
+			vec![ipv6(1), ipv4(1), ipv4(2), ipv4(3)]
+		);
+	}
+
+	#[test]
+	fn interleaving_keeps_empty_input_empty() {
+		assert!(interleave_address_families(Vec::new()).is_empty());
+	}
+
+	/// How a fake connection attempt to an address behaves.
+	#[derive(Clone, Copy)]
+	enum Outcome {
+		SucceedAfter(Duration),
+		FailAfter(Duration),
+		Hang,
+	}
+
+	/// Races fake connection attempts and returns the winner together with when each attempt was started.
+	async fn race_fake_attempts(
+		attempts: Vec<(SocketAddr, Outcome)>,
+		attempt_delay: Duration,
+	) -> (tokio::io::Result<SocketAddr>, Vec<(SocketAddr, Duration)>) {
+		let start = Instant::now();
+		let started = Arc::new(Mutex::new(Vec::new()));
+		let addresses = attempts.iter().map(|(address, _)| *address).collect();
+		let result = race(addresses, attempt_delay, |address| {
+			started.lock().unwrap().push((address, start.elapsed()));
+			let outcome = attempts
+				.iter()
+				.find_map(|(candidate, outcome)| (*candidate == address).then_some(*outcome))
+				.unwrap();
+			async move {
+				match outcome {
+					Outcome::SucceedAfter(duration) => {
+						tokio::time::sleep(duration).await;
+						Ok(address)
+					}
+					Outcome::FailAfter(duration) => {
+						tokio::time::sleep(duration).await;
+						Err(tokio::io::Error::from(ErrorKind::ConnectionRefused))
+					}
+					Outcome::Hang => std::future::pending().await,
+				}
+			}
+		})
+		.await;
+		let started = started.lock().unwrap().clone();
+		(result, started)
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn next_attempt_starts_after_the_attempt_delay() {
+		let (result, started) = race_fake_attempts(
+			vec![
+				(ipv6(1), Outcome::Hang),
+				(ipv4(1), Outcome::SucceedAfter(Duration::from_millis(10))),
+			],
+			Duration::from_millis(250),
+		)
+		.await;
+		assert_eq!(result.unwrap(), ipv4(1));
+		assert_eq!(
+			started,
+			vec![(ipv6(1), Duration::ZERO), (ipv4(1), Duration::from_millis(250))]
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn failed_attempt_starts_the_next_one_immediately() {
+		let (result, started) = race_fake_attempts(
+			vec![
+				(ipv6(1), Outcome::FailAfter(Duration::from_millis(10))),
+				(ipv4(1), Outcome::SucceedAfter(Duration::from_millis(10))),
+			],
+			Duration::from_millis(250),
+		)
+		.await;
+		assert_eq!(result.unwrap(), ipv4(1));
+		assert_eq!(
+			started,
+			vec![(ipv6(1), Duration::ZERO), (ipv4(1), Duration::from_millis(10))]
+		);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn last_error_is_returned_if_all_attempts_fail() {
+		let (result, started) = race_fake_attempts(
+			vec![
+				(ipv6(1), Outcome::FailAfter(Duration::from_millis(10))),
+				(ipv4(1), Outcome::FailAfter(Duration::from_millis(10))),
+			],
+			Duration::from_millis(250),
+		)
+		.await;
+		assert_eq!(result.unwrap_err().kind(), ErrorKind::ConnectionRefused);
+		assert_eq!(started.len(), 2);
+	}
+
+	#[tokio::test]
+	async fn no_addresses_is_an_error() {
+		let (result, started) = race_fake_attempts(Vec::new(), Duration::from_millis(250)).await;
+		assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidInput);
+		assert!(started.is_empty());
+	}
+}
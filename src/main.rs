@@ -1,23 +1,50 @@
 //! https://datatracker.ietf.org/doc/html/rfc1928
 
-use crate::server::listen_for_tcp_connections;
+use crate::access_log::{AccessLog, AccessLogFormat};
+use crate::rate_limit::BandwidthLimit;
+use crate::server::{listen_for_tcp_connections, ConnectionLimitBehavior, ServerConfig};
 use anyhow::{bail, Context};
-use clap::Parser;
-use std::io::{stdout, IsTerminal};
-use std::net::SocketAddr;
+use clap::builder::RangedU64ValueParser;
+use clap::parser::ValueSource;
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser};
+use std::io::{stderr, stdout, IsTerminal, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::oneshot;
+use tokio::net::TcpListener;
+use tokio::sync::{oneshot, watch, Semaphore};
 use tokio::task::JoinSet;
-use tracing::info;
+use tracing::{info, warn};
+use tracing_subscriber::fmt::format::FmtSpan;
 use tracing_subscriber::EnvFilter;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
-	let parameters = Parameters::parse();
+	let matches = Parameters::command().get_matches();
+	let parameters = Parameters::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+	let listen_addresses_given = matches
+		.value_source("listen_addresses")
+		.is_some_and(|source| source != ValueSource::DefaultValue);
 
+	// Keep stdout machine-readable if the access log is written there
+	let log_to_stderr = parameters.access_log_uses_stdout();
+	let log_is_terminal = if log_to_stderr {
+		stderr().is_terminal()
+	} else {
+		stdout().is_terminal()
+	};
 	tracing_subscriber::fmt()
-		.with_ansi(stdout().is_terminal())
+		.with_ansi(log_is_terminal)
+		.with_writer(move || -> Box<dyn Write> {
+			if log_to_stderr {
+				Box::new(stderr())
+			} else {
+				Box::new(stdout())
+			}
+		})
 		.with_env_filter(EnvFilter::new(&parameters.log_filter))
+		.with_span_events(FmtSpan::CLOSE)
 		.init();
 
 	let (shutdown_sender, shutdown_receiver) = oneshot::channel();
@@ -29,30 +56,74 @@ async fn main() -> anyhow::Result<()> {
 	})
 	.context("Failed to register Ctrl-C handler")?;
 
+	let (session_shutdown, session_shutdown_receiver) = watch::channel(false);
+	let config = parameters.server_config(session_shutdown_receiver)?;
+	let access_log = config.access_log.clone();
 	let mut join_set = JoinSet::new();
-	for listen_address in parameters.listen_addresses.iter().copied() {
-		join_set.spawn(listen_for_tcp_connections(listen_address, parameters.connect_timeout()));
+	for listener in create_listeners(&parameters.listen_addresses, listen_addresses_given).await? {
+		join_set.spawn(listen_for_tcp_connections(listener, config.clone()));
 	}
+	drop(config);
 
-	tokio::select! {
-		option = join_set.join_next() => {
-			match option {
-				Some(result) => result??,
-				None => bail!("No listen adddress specified."),
-			};
-		}
-		_ = shutdown_receiver => {
-			info!("Received ctrl-c, shutting down");
-			join_set.shutdown().await;
+	let result = async {
+		tokio::select! {
+			option = join_set.join_next() => {
+				match option {
+					Some(result) => result??,
+					None => bail!("No listen adddress specified."),
+				};
+			}
+			_ = shutdown_receiver => {
+				info!("Received ctrl-c, shutting down");
+			}
+		};
+		Ok(())
+	}
+	.await;
+
+	// End running sessions and wait for them to finish, so all access log records get written
+	let _ = session_shutdown.send(true);
+	join_set.shutdown().await;
+	session_shutdown.closed().await;
+	if let Some(access_log) = access_log.and_then(Arc::into_inner) {
+		access_log.close();
+	}
+
+	result
+}
+
+/// Uses the sockets passed in via systemd socket activation if there are any,
+/// otherwise binds to `listen_addresses`.
+async fn create_listeners(
+	listen_addresses: &[SocketAddr],
+	listen_addresses_given: bool,
+) -> anyhow::Result<Vec<TcpListener>> {
+	#[cfg(target_os = "linux")]
+	let inherited_listeners = systemd::inherited_listeners()?;
+	#[cfg(not(target_os = "linux"))]
+	let inherited_listeners: Option<Vec<TcpListener>> = None;
+
+	if let Some(listeners) = inherited_listeners {
+		if listen_addresses_given {
+			warn!("Ignoring the given listen addresses because of systemd socket activation");
 		}
-	};
+		info!("Using {} socket(s) from systemd socket activation", listeners.len());
+		return Ok(listeners);
+	}
 
-	Ok(())
+	let mut listeners = Vec::with_capacity(listen_addresses.len());
+	for listen_address in listen_addresses {
+		let listener = TcpListener::bind(listen_address)
+			.await
+			.with_context(|| format!("Failed to listen on {listen_address}"))?;
+		listeners.push(listener);
+	}
+	Ok(listeners)
 }
 
 #[derive(Debug, Parser)]
 struct Parameters {
-	/// IPv4 or IPv6 Address to listen on.
+	/// IPv4 or IPv6 Address to listen on. Ignored when started via systemd socket activation.
 	#[arg(
 		default_value = "127.0.0.1:1080",
 		env = "SOCKS_BIND_ADDRESSES",
@@ -61,15 +132,96 @@ struct Parameters {
 	listen_addresses: Vec<SocketAddr>,
 	#[arg(long, default_value = "info", env = "LOG_FILTER")]
 	log_filter: String,
+	/// Timeout for reading the handshake messages from the client.
+	#[arg(long, default_value = "10", env = "SOCKS_HANDSHAKE_TIMEOUT_SECONDS")]
+	handshake_timeout_seconds: u64,
+	/// Timeout for resolving and connecting to the destination.
 	#[arg(long, default_value = "10", env = "SOCKS_CONNECT_TIMEOUT_SECONDS")]
 	connect_timeout_seconds: u64,
+	/// Delay before racing a connection attempt to the next resolved address (Happy Eyeballs).
+	#[arg(long, default_value = "250", env = "SOCKS_CONNECTION_ATTEMPT_DELAY_MS")]
+	connection_attempt_delay_ms: u64,
+	/// Connect to destinations through this SOCKS5 proxy instead of directly.
+	#[arg(long, env = "SOCKS_UPSTREAM_PROXY")]
+	upstream_proxy: Option<SocketAddr>,
+	/// Local IP address to originate outgoing connections from.
+	#[arg(long, env = "SOCKS_OUTGOING_ADDRESS")]
+	bind_outgoing: Option<IpAddr>,
+	/// Maximum bytes per second per connection in each direction, 0 disables the limit.
+	#[arg(long, default_value = "0", env = "SOCKS_RATE_LIMIT_BYTES_PER_SEC")]
+	rate_limit_bytes_per_sec: u64,
+	/// Maximum bytes per second across all connections in each direction, 0 disables the limit.
+	#[arg(long, default_value = "0", env = "SOCKS_GLOBAL_RATE_LIMIT_BYTES_PER_SEC")]
+	global_rate_limit_bytes_per_sec: u64,
+	/// Maximum number of concurrent connections across all listen addresses, 0 means unlimited.
+	#[arg(
+		long,
+		default_value = "0",
+		env = "SOCKS_MAX_CONNECTIONS",
+		value_parser = RangedU64ValueParser::<usize>::new().range(..=Semaphore::MAX_PERMITS as u64)
+	)]
+	max_connections: usize,
+	/// What to do with new connections when `--max-connections` is reached.
+	#[arg(long, value_enum, default_value = "wait", env = "SOCKS_CONNECTION_LIMIT_BEHAVIOR")]
+	connection_limit_behavior: ConnectionLimitBehavior,
+	/// Disable Nagle's algorithm on client and upstream sockets.
+	#[arg(long, default_value_t = true, action = ArgAction::Set, env = "SOCKS_TCP_NODELAY")]
+	tcp_nodelay: bool,
+	/// Idle time and interval of TCP keepalive probes on client and upstream sockets, 0 disables keepalive.
+	#[arg(long, default_value = "0", env = "SOCKS_TCP_KEEPALIVE_SECONDS")]
+	tcp_keepalive_seconds: u64,
+	/// Write one access log record per session in this format, disabled if not specified.
+	#[arg(long, value_enum, env = "SOCKS_ACCESS_LOG_FORMAT")]
+	access_log_format: Option<AccessLogFormat>,
+	/// File to append the access log to. Without it the access log goes to stdout and all other logging to stderr.
+	#[arg(long, env = "SOCKS_ACCESS_LOG_FILE", requires = "access_log_format")]
+	access_log_file: Option<PathBuf>,
 }
 
 impl Parameters {
+	fn handshake_timeout(&self) -> Duration {
+		Duration::from_secs(self.handshake_timeout_seconds)
+	}
+
 	fn connect_timeout(&self) -> Duration {
 		Duration::from_secs(self.connect_timeout_seconds)
 	}
+
+	fn access_log_uses_stdout(&self) -> bool {
+		self.access_log_format.is_some() && self.access_log_file.is_none()
+	}
+
+	fn server_config(&self, shutdown: watch::Receiver<bool>) -> anyhow::Result<ServerConfig> {
+		let access_log = self
+			.access_log_format
+			.map(|format| AccessLog::open(format, self.access_log_file.as_deref()))
+			.transpose()?
+			.map(Arc::new);
+
+		Ok(ServerConfig {
+			handshake_timeout: self.handshake_timeout(),
+			connect_timeout: self.connect_timeout(),
+			connection_attempt_delay: Duration::from_millis(self.connection_attempt_delay_ms),
+			upstream_proxy: self.upstream_proxy,
+			outgoing_address: self.bind_outgoing,
+			connection_rate_limit: self.rate_limit_bytes_per_sec,
+			global_bandwidth_limit: BandwidthLimit::new(self.global_rate_limit_bytes_per_sec).map(Arc::new),
+			connection_limit: (self.max_connections != 0).then(|| Arc::new(Semaphore::new(self.max_connections))),
+			connection_limit_behavior: self.connection_limit_behavior,
+			tcp_nodelay: self.tcp_nodelay,
+			tcp_keepalive: (self.tcp_keepalive_seconds != 0).then(|| Duration::from_secs(self.tcp_keepalive_seconds)),
+			access_log,
+			shutdown,
+		})
+	}
 }
 
+mod access_log;
+mod happy_eyeballs;
 mod message;
+mod outgoing;
+mod rate_limit;
 mod server;
+#[cfg(target_os = "linux")]
+mod systemd;
+mod upstream_proxy;
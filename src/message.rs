@@ -1,6 +1,7 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 /// > The VER field is set to X'05' for this version of the protocol.
@@ -18,7 +19,7 @@ pub const VERSION: u8 = 0x05;
 /// > The VER field is set to X'05' for this version of the protocol.  The
 /// > NMETHODS field contains the number of method identifier octets that
 /// > appear in the METHODS field.
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct MethodSelectionRequest {
 	pub methods: Vec<Method>,
 }
@@ -43,13 +44,37 @@ impl MethodSelectionRequest {
 		let methods = methods.into_iter().map(Method::from).collect();
 		Ok(Self { methods })
 	}
+
+	pub async fn write_to_stream<Stream>(&self, stream: &mut Stream) -> tokio::io::Result<()>
+	where
+		Stream: AsyncWrite + Unpin,
+	{
+		let method_count = u8::try_from(self.methods.len())
+			.map_err(|_| tokio::io::Error::new(ErrorKind::InvalidInput, "More than 255 methods"))?;
+		stream.write_all(&[VERSION, method_count]).await?;
+		let methods: Vec<u8> = self.methods.iter().copied().map(u8::from).collect();
+		stream.write_all(&methods).await
+	}
 }
 
+#[derive(Debug, PartialEq, Eq)]
 pub struct MethodSelectionResponse {
 	pub method: Method,
 }
 
 impl MethodSelectionResponse {
+	pub async fn parse_from_stream<Stream>(stream: &mut Stream) -> Result<Self, ParseError>
+	where
+		Stream: AsyncRead + Unpin,
+	{
+		if stream.read_u8().await? != VERSION {
+			return Err(ParseError::InvalidVersion);
+		}
+
+		let method = Method::from(stream.read_u8().await?);
+		Ok(Self { method })
+	}
+
 	pub async fn write_to_stream<Stream>(&self, stream: &mut Stream) -> tokio::io::Result<()>
 	where
 		Stream: AsyncWrite + Unpin,
@@ -104,11 +129,41 @@ pub enum Method {
 	NoAuthenticationRequired,
 	GssApi,
 	UsernamePassword,
-	IanaAssigned(u8),              // TODO: Prevent invalid values
-	ReservedForPrivateMethods(u8), // TODO: Prevent invalid values
+	IanaAssigned(IanaAssignedMethod),
+	ReservedForPrivateMethods(PrivateMethod),
 	NoAcceptableMethods,
 }
 
+impl Method {
+	/// Returns `None` unless `method` is in the range X'03' to X'7F'.
+	pub fn iana_assigned(method: u8) -> Option<Self> {
+		match method {
+			0x03..=0x7f => Some(Self::IanaAssigned(IanaAssignedMethod(method))),
+			_ => None,
+		}
+	}
+
+	/// Returns `None` unless `method` is in the range X'80' to X'FE'.
+	pub fn reserved_for_private_methods(method: u8) -> Option<Self> {
+		match method {
+			0x80..=0xfe => Some(Self::ReservedForPrivateMethods(PrivateMethod(method))),
+			_ => None,
+		}
+	}
+}
+
+/// > * X'03' to X'7F' IANA ASSIGNED
+///
+/// Can only be constructed with a value from that range via [`Method::iana_assigned`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct IanaAssignedMethod(u8);
+
+/// > * X'80' to X'FE' RESERVED FOR PRIVATE METHODS
+///
+/// Can only be constructed with a value from that range via [`Method::reserved_for_private_methods`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PrivateMethod(u8);
+
 impl From<u8> for Method {
 	fn from(method: u8) -> Self {
 		match method {
@@ -119,9 +174,9 @@ impl From<u8> for Method {
 			// X'02' USERNAME/PASSWORD
 			0x02 => Self::UsernamePassword,
 			// X'03' to X'7F' IANA ASSIGNED
-			0x03..=0x7f => Self::IanaAssigned(method),
+			0x03..=0x7f => Self::IanaAssigned(IanaAssignedMethod(method)),
 			// X'80' to X'FE' RESERVED FOR PRIVATE METHODS
-			0x80..=0xfe => Self::ReservedForPrivateMethods(method),
+			0x80..=0xfe => Self::ReservedForPrivateMethods(PrivateMethod(method)),
 			// X'FF' NO ACCEPTABLE METHODS
 			0xff => Self::NoAcceptableMethods,
 		}
@@ -139,11 +194,9 @@ impl From<Method> for u8 {
 			// X'02' USERNAME/PASSWORD
 			UsernamePassword => 0x02,
 			// X'03' to X'7F' IANA ASSIGNED
-			IanaAssigned(method @ 0x03..=0x7f) => method,
-			IanaAssigned(_) => unreachable!(),
+			IanaAssigned(IanaAssignedMethod(method)) => method,
 			// X'80' to X'FE' RESERVED FOR PRIVATE METHODS
-			ReservedForPrivateMethods(method @ 0x80..=0xfe) => method,
-			ReservedForPrivateMethods(_) => unreachable!(),
+			ReservedForPrivateMethods(PrivateMethod(method)) => method,
 			// X'FF' NO ACCEPTABLE METHODS
 			NoAcceptableMethods => 0xff,
 		}
@@ -171,7 +224,7 @@ impl From<Method> for u8 {
 /// >    * IP V6 address: X'04'
 /// >  * DST.ADDR  desired destination address
 /// >  * DST.PORT  desired destination port in network octet order
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct SocksRequest {
 	pub command: Command,
 	pub address: Address,
@@ -200,13 +253,25 @@ impl SocksRequest {
 
 		Ok(Self { command, address, port })
 	}
+
+	pub async fn write_to_stream<Stream>(&self, stream: &mut Stream) -> tokio::io::Result<()>
+	where
+		Stream: AsyncWrite + Unpin,
+	{
+		let Self { command, address, port } = self;
+
+		const RESERVED: u8 = 0x00;
+		stream.write_all(&[VERSION, *command as u8, RESERVED]).await?;
+		address.write_to_stream(stream).await?;
+		stream.write_u16(*port).await
+	}
 }
 
 /// > * CMD
 /// >   * CONNECT X'01'
 /// >   * BIND X'02'
 /// >   * UDP ASSOCIATE X'03'
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[repr(u8)]
 pub enum Command {
 	Connect = 0x01,
@@ -240,6 +305,7 @@ pub enum Command {
 /// >   * X'09' to X'FF' unassigned
 /// > * RSV  RESERVED
 /// > * ATYP  address type of following address
+#[derive(Debug, PartialEq, Eq)]
 pub struct SocksResponse {
 	pub reply: SocksReply,
 	pub address: Address,
@@ -247,6 +313,45 @@ pub struct SocksResponse {
 }
 
 impl SocksResponse {
+	pub async fn parse_from_stream<Stream>(stream: &mut Stream) -> Result<Self, ParseError>
+	where
+		Stream: AsyncRead + Unpin,
+	{
+		if stream.read_u8().await? != VERSION {
+			return Err(ParseError::InvalidVersion);
+		}
+
+		let reply = SocksReply::from(stream.read_u8().await?);
+
+		const RESERVED: u8 = 0x00;
+		if stream.read_u8().await? != RESERVED {
+			return Err(ParseError::MissingReserved);
+		}
+
+		let address = Address::parse_from_stream(stream).await?;
+
+		let port = stream.read_u16().await?;
+
+		Ok(Self { reply, address, port })
+	}
+
+	/// > In the reply to a CONNECT, BND.PORT contains the port number that the
+	/// > server assigned to connect to the target host, while BND.ADDR
+	/// > contains the associated IP address.
+	pub fn new(reply: SocksReply, bind_address: SocketAddr) -> Self {
+		Self {
+			reply,
+			address: bind_address.ip().into(),
+			port: bind_address.port(),
+		}
+	}
+
+	/// There is no bind address when the request failed, so this uses 0.0.0.0:0 instead.
+	/// NOTE: OpenSSH even unconditionally returns 0.0.0.0:0 for successful requests! https://github.com/openssh/openssh-portable/blob/800c2483e68db38bd1566ff69677124be974aceb/channels.c#L1512
+	pub fn failure(reply: SocksReply) -> Self {
+		Self::new(reply, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)))
+	}
+
 	pub async fn write_to_stream<Stream>(&self, stream: &mut Stream) -> tokio::io::Result<()>
 	where
 		Stream: AsyncWrite + Unpin,
@@ -271,7 +376,7 @@ impl SocksResponse {
 /// >   * X'07' Command not supported
 /// >   * X'08' Address type not supported
 /// >   * X'09' to X'FF' unassigned
-#[derive(Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum SocksReply {
 	Succeeded,
 	GeneralSocksServerFailure,
@@ -285,6 +390,33 @@ pub enum SocksReply {
 	Unassigned(u8),
 }
 
+impl From<u8> for SocksReply {
+	fn from(reply: u8) -> Self {
+		match reply {
+			// X'00' succeeded
+			0x00 => Self::Succeeded,
+			// X'01' general SOCKS server failure
+			0x01 => Self::GeneralSocksServerFailure,
+			// X'02' connection not allowed by ruleset
+			0x02 => Self::ConnectionNotAllowedByRuleset,
+			// X'03' Network unreachable
+			0x03 => Self::NetworkUnreachable,
+			// X'04' Host unreachable
+			0x04 => Self::HostUnreachable,
+			// X'05' Connection refused
+			0x05 => Self::ConnectionRefused,
+			// X'06' TTL expired
+			0x06 => Self::TtlExpired,
+			// X'07' Command not supported
+			0x07 => Self::CommandNotSupported,
+			// X'08' Address type not supported
+			0x08 => Self::AddressTypeNotSupported,
+			// X'09' to X'FF' unassigned
+			0x09..=0xff => Self::Unassigned(reply),
+		}
+	}
+}
+
 impl From<SocksReply> for u8 {
 	fn from(reply: SocksReply) -> Self {
 		use SocksReply::*;
@@ -335,7 +467,7 @@ impl TryFrom<u8> for Command {
 /// >   * DOMAINNAME: X'03'
 /// >   * IP V6 address: X'04'
 /// > * DST.ADDR  desired destination address
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Address {
 	Ipv4(Ipv4Addr),
 	DomainName(Vec<u8>),
@@ -383,7 +515,7 @@ impl Address {
 			Ipv4(ipv4) => stream.write_all(&ipv4.octets()).await,
 			DomainName(domain) => {
 				let length = u8::try_from(domain.len())
-					.unwrap_or_else(|_| unreachable!("Domain name cannot be longer than 255 bytes"));
+					.map_err(|_| tokio::io::Error::new(ErrorKind::InvalidInput, "Domain name longer than 255 bytes"))?;
 				stream.write_u8(length).await?;
 				stream.write_all(domain).await
 			}
@@ -415,6 +547,19 @@ impl Display for Address {
 	}
 }
 
+/// Displays an address followed by a port, with IPv6 addresses in brackets like [`SocketAddr`] does.
+pub struct AddressWithPort<'a>(pub &'a Address, pub u16);
+
+impl Display for AddressWithPort<'_> {
+	fn fmt(&self, formatter: &mut Formatter<'_>) -> std::fmt::Result {
+		let Self(address, port) = self;
+		match address {
+			Address::Ipv6(ipv6) => write!(formatter, "[{ipv6}]:{port}"),
+			address => write!(formatter, "{address}:{port}"),
+		}
+	}
+}
+
 impl From<IpAddr> for Address {
 	fn from(address: IpAddr) -> Self {
 		match address {
@@ -423,3 +568,148 @@ impl From<IpAddr> for Address {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::io::duplex;
+
+	#[test]
+	fn iana_assigned_respects_range_boundaries() {
+		for (method, valid) in [(0x02, false), (0x03, true), (0x7f, true), (0x80, false)] {
+			assert_eq!(Method::iana_assigned(method).is_some(), valid, "{method:#04x}");
+		}
+	}
+
+	#[test]
+	fn reserved_for_private_methods_respects_range_boundaries() {
+		for (method, valid) in [(0x7f, false), (0x80, true), (0xfe, true), (0xff, false)] {
+			assert_eq!(
+				Method::reserved_for_private_methods(method).is_some(),
+				valid,
+				"{method:#04x}"
+			);
+		}
+	}
+
+	#[test]
+	fn constructors_match_parsing() {
+		assert_eq!(Method::iana_assigned(0x03), Some(Method::from(0x03)));
+		assert_eq!(Method::reserved_for_private_methods(0xfe), Some(Method::from(0xfe)));
+	}
+
+	#[test]
+	fn method_round_trips_through_u8() {
+		for method in 0x00..=0xff {
+			assert_eq!(u8::from(Method::from(method)), method);
+		}
+	}
+
+	#[test]
+	fn address_with_port_brackets_ipv6() {
+		let addresses = [
+			(Address::Ipv4(Ipv4Addr::LOCALHOST), "127.0.0.1:1080"),
+			(Address::DomainName(b"example.com".to_vec()), "example.com:1080"),
+			(Address::Ipv6(Ipv6Addr::LOCALHOST), "[::1]:1080"),
+		];
+		for (address, expected) in addresses {
+			assert_eq!(AddressWithPort(&address, 1080).to_string(), expected);
+		}
+	}
+
+	#[test]
+	fn socks_reply_round_trips_through_u8() {
+		for reply in 0x00..=0xff {
+			assert_eq!(u8::from(SocksReply::from(reply)), reply);
+		}
+	}
+
+	fn test_addresses() -> [Address; 3] {
+		[
+			Address::Ipv4(Ipv4Addr::new(192, 0, 2, 1)),
+			Address::DomainName(b"example.com".to_vec()),
+			Address::Ipv6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)),
+		]
+	}
+
+	#[tokio::test]
+	async fn method_selection_request_round_trips() {
+		let request = MethodSelectionRequest {
+			methods: vec![
+				Method::NoAuthenticationRequired,
+				Method::UsernamePassword,
+				Method::from(0x42),
+				Method::from(0x80),
+			],
+		};
+		let (mut client, mut server) = duplex(1024);
+		request.write_to_stream(&mut client).await.unwrap();
+		assert_eq!(
+			MethodSelectionRequest::parse_from_stream(&mut server).await.unwrap(),
+			request
+		);
+	}
+
+	#[tokio::test]
+	async fn method_selection_request_rejects_too_many_methods() {
+		let request = MethodSelectionRequest {
+			methods: vec![Method::NoAuthenticationRequired; 256],
+		};
+		let (mut client, _server) = duplex(1024);
+		let error = request.write_to_stream(&mut client).await.unwrap_err();
+		assert_eq!(error.kind(), ErrorKind::InvalidInput);
+	}
+
+	#[tokio::test]
+	async fn method_selection_response_round_trips() {
+		for method in [Method::NoAuthenticationRequired, Method::NoAcceptableMethods] {
+			let response = MethodSelectionResponse { method };
+			let (mut client, mut server) = duplex(1024);
+			response.write_to_stream(&mut server).await.unwrap();
+			assert_eq!(
+				MethodSelectionResponse::parse_from_stream(&mut client).await.unwrap(),
+				response
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn socks_request_round_trips() {
+		for address in test_addresses() {
+			let request = SocksRequest {
+				command: Command::Connect,
+				address,
+				port: 443,
+			};
+			let (mut client, mut server) = duplex(1024);
+			request.write_to_stream(&mut client).await.unwrap();
+			assert_eq!(SocksRequest::parse_from_stream(&mut server).await.unwrap(), request);
+		}
+	}
+
+	#[tokio::test]
+	async fn socks_request_rejects_too_long_domain_names() {
+		let request = SocksRequest {
+			command: Command::Connect,
+			address: Address::DomainName(vec![b'a'; 256]),
+			port: 443,
+		};
+		let (mut client, _server) = duplex(1024);
+		let error = request.write_to_stream(&mut client).await.unwrap_err();
+		assert_eq!(error.kind(), ErrorKind::InvalidInput);
+	}
+
+	#[tokio::test]
+	async fn socks_response_round_trips() {
+		for address in test_addresses() {
+			let response = SocksResponse {
+				reply: SocksReply::Succeeded,
+				address,
+				port: 50000,
+			};
+			let (mut client, mut server) = duplex(1024);
+			response.write_to_stream(&mut server).await.unwrap();
+			assert_eq!(SocksResponse::parse_from_stream(&mut client).await.unwrap(), response);
+		}
+	}
+}
@@ -0,0 +1,32 @@
+//! Helpers for establishing connections from the proxy to the outside world.
+
+use crate::message::SocksReply;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Connects to `address`, binding to `outgoing_address` first if specified.
+pub async fn connect_from(address: SocketAddr, outgoing_address: Option<IpAddr>) -> std::io::Result<TcpStream> {
+	let Some(outgoing_address) = outgoing_address else {
+		return TcpStream::connect(address).await;
+	};
+
+	let socket = match address {
+		SocketAddr::V4(_) => TcpSocket::new_v4()?,
+		SocketAddr::V6(_) => TcpSocket::new_v6()?,
+	};
+	socket.bind(SocketAddr::new(outgoing_address, 0))?;
+	socket.connect(address).await
+}
+
+pub fn is_same_address_family(outgoing_address: IpAddr, address: SocketAddr) -> bool {
+	outgoing_address.is_ipv4() == address.is_ipv4()
+}
+
+pub fn reply_for_connect_error(error: &std::io::Error) -> SocksReply {
+	use std::io::ErrorKind::*;
+	match error.kind() {
+		PermissionDenied => SocksReply::ConnectionNotAllowedByRuleset,
+		ConnectionRefused => SocksReply::ConnectionRefused,
+		_ => SocksReply::GeneralSocksServerFailure,
+	}
+}
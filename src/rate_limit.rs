@@ -0,0 +1,138 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::Instant;
+
+/// Limits the throughput in both directions of a connection (or of all connections, if shared).
+pub struct BandwidthLimit {
+	pub upload: TokenBucket,
+	pub download: TokenBucket,
+}
+
+impl BandwidthLimit {
+	/// Returns `None` if `bytes_per_second` is 0, meaning unlimited.
+	pub fn new(bytes_per_second: u64) -> Option<Self> {
+		if bytes_per_second == 0 {
+			return None;
+		}
+
+		Some(Self {
+			upload: TokenBucket::new(bytes_per_second),
+			download: TokenBucket::new(bytes_per_second),
+		})
+	}
+}
+
+/// Token bucket that refills at a constant rate and can hold at most one second worth of bytes.
+///
+/// Consuming more bytes than are available puts the bucket into debt, the consumer then waits
+/// until the debt has been paid off by refilling.
+pub struct TokenBucket {
+	bytes_per_second: f64,
+	state: Mutex<TokenBucketState>,
+}
+
+struct TokenBucketState {
+	available_bytes: f64,
+	last_refill: Instant,
+}
+
+impl TokenBucket {
+	fn new(bytes_per_second: u64) -> Self {
+		let bytes_per_second = bytes_per_second as f64;
+		Self {
+			bytes_per_second,
+			state: Mutex::new(TokenBucketState {
+				available_bytes: bytes_per_second,
+				last_refill: Instant::now(),
+			}),
+		}
+	}
+
+	pub async fn consume(&self, bytes: usize) {
+		let debt = {
+			let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+			let now = Instant::now();
+			let refill = now.duration_since(state.last_refill).as_secs_f64() * self.bytes_per_second;
+			state.available_bytes = (state.available_bytes + refill).min(self.bytes_per_second);
+			state.last_refill = now;
+			state.available_bytes -= bytes as f64;
+			-state.available_bytes
+		};
+
+		if debt > 0.0 {
+			tokio::time::sleep(Duration::from_secs_f64(debt / self.bytes_per_second)).await;
+		}
+	}
+}
+
+/// Copies from `reader` to `writer` until EOF, waiting for every bucket to allow the bytes before writing them.
+/// Shuts down the writer once the reader reached EOF, so half-closed connections are forwarded.
+/// `total_bytes` is kept up to date even if copying fails.
+pub async fn throttled_copy<Reader, Writer>(
+	reader: &mut Reader,
+	writer: &mut Writer,
+	buckets: &[&TokenBucket],
+	total_bytes: &mut u64,
+) -> tokio::io::Result<()>
+where
+	Reader: AsyncRead + Unpin,
+	Writer: AsyncWrite + Unpin,
+{
+	let mut buffer = vec![0u8; 8 * 1024];
+	loop {
+		let length = reader.read(&mut buffer).await?;
+		if length == 0 {
+			writer.shutdown().await?;
+			return Ok(());
+		}
+
+		for bucket in buckets {
+			bucket.consume(length).await;
+		}
+
+		writer.write_all(&buffer[..length]).await?;
+		*total_bytes += length as u64;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::io::duplex;
+
+	#[test]
+	fn zero_disables_the_limit() {
+		assert!(BandwidthLimit::new(0).is_none());
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn throttled_copy_respects_the_limit() {
+		const BYTES_PER_SECOND: u64 = 1000;
+		let bucket = TokenBucket::new(BYTES_PER_SECOND);
+		let buckets = [&bucket];
+		let payload = vec![0x42u8; 3 * BYTES_PER_SECOND as usize];
+
+		let (mut source, mut reader) = duplex(64 * 1024);
+		let (mut writer, mut sink) = duplex(64 * 1024);
+		let start = Instant::now();
+		let mut total_bytes = 0;
+		let mut received = Vec::new();
+		let (send_result, copy_result, receive_result) = tokio::join!(
+			async {
+				source.write_all(&payload).await?;
+				source.shutdown().await
+			},
+			throttled_copy(&mut reader, &mut writer, &buckets, &mut total_bytes),
+			sink.read_to_end(&mut received),
+		);
+		send_result.unwrap();
+		copy_result.unwrap();
+		receive_result.unwrap();
+
+		// The bucket starts out full, so the first second worth of bytes passes immediately.
+		assert!(start.elapsed() >= Duration::from_secs(2));
+		assert_eq!(total_bytes, payload.len() as u64);
+		assert_eq!(received, payload);
+	}
+}
@@ -1,39 +1,176 @@
+use crate::access_log::{AccessLog, AccessLogRecord};
+use crate::happy_eyeballs;
 use crate::message::{
-	Address, Command, Method, MethodSelectionRequest, MethodSelectionResponse, SocksReply, SocksRequest, SocksResponse,
+	Address, AddressWithPort, Command, Method, MethodSelectionRequest, MethodSelectionResponse, SocksReply,
+	SocksRequest, SocksResponse,
 };
+use crate::outgoing::{is_same_address_family, reply_for_connect_error};
+use crate::rate_limit::{throttled_copy, BandwidthLimit};
+use crate::upstream_proxy;
 use anyhow::{anyhow, bail};
-use std::io::ErrorKind;
-use std::net::SocketAddr;
+use clap::ValueEnum;
+use socket2::{SockRef, TcpKeepalive};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
 use tokio::time::error::Elapsed;
-use tracing::{debug, error, info};
+use tracing::field::Empty;
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 
-pub async fn listen_for_tcp_connections(socket_address: SocketAddr, connect_timeout: Duration) -> anyhow::Result<()> {
-	let listener = TcpListener::bind(socket_address).await?;
+#[derive(Clone)]
+pub struct ServerConfig {
+	/// Time budget for reading the method selection request and the SOCKS request from the client.
+	pub handshake_timeout: Duration,
+	/// Time budget for resolving and connecting to the destination.
+	pub connect_timeout: Duration,
+	/// Delay before starting the next connection attempt to another resolved address.
+	pub connection_attempt_delay: Duration,
+	/// SOCKS5 proxy to connect through instead of connecting to the destination directly.
+	pub upstream_proxy: Option<SocketAddr>,
+	/// Source address for outgoing connections, `None` lets the OS choose.
+	pub outgoing_address: Option<IpAddr>,
+	/// Bytes per second per connection and direction, 0 means unlimited.
+	pub connection_rate_limit: u64,
+	/// Shared between all connections.
+	pub global_bandwidth_limit: Option<Arc<BandwidthLimit>>,
+	/// Shared between all listeners, `None` means unlimited.
+	pub connection_limit: Option<Arc<Semaphore>>,
+	pub connection_limit_behavior: ConnectionLimitBehavior,
+	/// Applied to both the client and the upstream socket.
+	pub tcp_nodelay: bool,
+	/// Applied to both the client and the upstream socket, `None` disables keepalive.
+	pub tcp_keepalive: Option<Duration>,
+	pub access_log: Option<Arc<AccessLog>>,
+	/// Becomes `true` once the server shuts down, running sessions are then ended so they still get logged.
+	pub shutdown: watch::Receiver<bool>,
+}
+
+/// What to do with new connections once the connection limit has been reached.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ConnectionLimitBehavior {
+	/// Keep new connections waiting until a running connection finishes.
+	Wait,
+	/// Accept new connections and close them immediately.
+	Reject,
+}
+
+pub async fn listen_for_tcp_connections(listener: TcpListener, config: ServerConfig) -> anyhow::Result<()> {
+	let socket_address = listener.local_addr()?;
 	info!(address = %socket_address.ip(), port = socket_address.port(), "Listening for connections");
+	// Shared between all listeners so connection IDs are unique across the whole server
+	static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
 	loop {
 		let (tcp_stream, client_address) = listener.accept().await?;
-		info!(address = %client_address.ip(), port = client_address.port(), "New connection");
-		tokio::spawn(async move {
-			if let Err(error) = run_socks_protocol(tcp_stream, connect_timeout).await {
-				error!(address = %client_address.ip(), port = client_address.port(), "Proxy task encountered error: {error}");
+		let span = info_span!(
+			"connection",
+			id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+			client = %client_address,
+			destination = Empty,
+			upstream = Empty,
+			request_bytes = Empty,
+			response_bytes = Empty,
+		);
+		span.in_scope(|| info!("New connection"));
+
+		// The permit is only acquired after accepting, so idle listeners don't hold on to permits
+		let permit = match (&config.connection_limit, config.connection_limit_behavior) {
+			(None, _) => None,
+			(Some(connection_limit), ConnectionLimitBehavior::Wait) => Some(
+				acquire_connection_permit(connection_limit.clone())
+					.instrument(span.clone())
+					.await?,
+			),
+			(Some(connection_limit), ConnectionLimitBehavior::Reject) => {
+				match connection_limit.clone().try_acquire_owned() {
+					Ok(permit) => Some(permit),
+					Err(_) => {
+						span.in_scope(|| warn!("Connection limit reached, closing connection"));
+						if let Some(access_log) = &config.access_log {
+							let mut access_log_record = AccessLogRecord::new(client_address);
+							access_log_record.error = Some("Connection limit reached".to_owned());
+							access_log.write(&access_log_record);
+						}
+						continue;
+					}
+				}
 			}
-		});
+		};
+
+		let config = config.clone();
+		tokio::spawn(
+			async move {
+				if let Err(error) = run_socks_protocol(tcp_stream, client_address, config).await {
+					error!("Proxy task encountered error: {error}");
+				}
+				drop(permit);
+			}
+			.instrument(span),
+		);
+	}
+}
+
+async fn acquire_connection_permit(connection_limit: Arc<Semaphore>) -> anyhow::Result<OwnedSemaphorePermit> {
+	if connection_limit.available_permits() == 0 {
+		warn!("Connection limit reached, waiting for connections to finish");
 	}
+	Ok(connection_limit.acquire_owned().await?)
 }
 
-async fn run_socks_protocol(mut client_stream: TcpStream, connect_timeout: Duration) -> anyhow::Result<()> {
-	let server_stream = tokio::time::timeout(connect_timeout, handshake_and_connect(&mut client_stream))
+async fn run_socks_protocol(
+	client_stream: TcpStream,
+	client_address: SocketAddr,
+	config: ServerConfig,
+) -> anyhow::Result<()> {
+	let mut access_log_record = AccessLogRecord::new(client_address);
+	let mut shutdown = config.shutdown.clone();
+	let result = tokio::select! {
+		result = proxy_session(client_stream, &config, &mut access_log_record) => result,
+		_ = shutdown.wait_for(|&shutdown| shutdown) => Err(anyhow!("Server shut down")),
+	};
+	// Recorded here instead of in `proxy_data`, so sessions ended by the shutdown get their byte counts as well
+	Span::current()
+		.record("request_bytes", access_log_record.request_bytes)
+		.record("response_bytes", access_log_record.response_bytes);
+	if let Some(access_log) = &config.access_log {
+		access_log_record.error = result.as_ref().err().map(ToString::to_string);
+		access_log.write(&access_log_record);
+	}
+	result
+}
+
+async fn proxy_session(
+	mut client_stream: TcpStream,
+	config: &ServerConfig,
+	access_log_record: &mut AccessLogRecord,
+) -> anyhow::Result<()> {
+	configure_socket(&client_stream, config)?;
+
+	let socks_request = tokio::time::timeout(config.handshake_timeout, handshake(&mut client_stream))
 		.await
-		.map_err(|_: Elapsed| anyhow!("Handshake and connection timed out"))??;
+		.map_err(|_: Elapsed| anyhow!("Handshake timed out"))??;
+	access_log_record.command = Some(socks_request.command);
+	access_log_record.destination = Some((socks_request.address.clone(), socks_request.port));
 
-	proxy_data(client_stream, server_stream).await;
+	let server_stream = connect(&mut client_stream, socks_request, config, access_log_record).await?;
+	access_log_record.upstream_address = server_stream.peer_addr().ok();
 
-	Ok(())
+	let connection_bandwidth_limit = BandwidthLimit::new(config.connection_rate_limit);
+	proxy_data(
+		client_stream,
+		server_stream,
+		connection_bandwidth_limit.as_ref(),
+		config.global_bandwidth_limit.as_deref(),
+		&mut access_log_record.request_bytes,
+		&mut access_log_record.response_bytes,
+	)
+	.await
+	.map_err(|error| anyhow!("Error proxying: {error}"))
 }
 
-async fn handshake_and_connect(client_stream: &mut TcpStream) -> anyhow::Result<TcpStream> {
+async fn handshake(client_stream: &mut TcpStream) -> anyhow::Result<SocksRequest> {
 	let method_selection_request = MethodSelectionRequest::parse_from_stream(client_stream).await?;
 	debug!("{method_selection_request:?}");
 	match select_method(method_selection_request.methods) {
@@ -48,15 +185,25 @@ async fn handshake_and_connect(client_stream: &mut TcpStream) -> anyhow::Result<
 
 	let socks_request = SocksRequest::parse_from_stream(client_stream).await?;
 	debug!("{socks_request:?}");
+	Ok(socks_request)
+}
 
-	Ok(match perform_socks_request(socks_request).await {
+async fn connect(
+	client_stream: &mut TcpStream,
+	socks_request: SocksRequest,
+	config: &ServerConfig,
+	access_log_record: &mut AccessLogRecord,
+) -> anyhow::Result<TcpStream> {
+	Ok(match perform_socks_request(socks_request, config).await {
 		Ok((proxy_stream, response)) => {
+			access_log_record.reply = Some(response.reply);
 			response.write_to_stream(client_stream).await?;
 			proxy_stream
 		}
-		Err(response) => {
+		Err((response, error)) => {
+			access_log_record.reply = Some(response.reply);
 			response.write_to_stream(client_stream).await?;
-			bail!("Failed to perform socks request, closing connection.");
+			return Err(error);
 		}
 	})
 }
@@ -73,60 +220,98 @@ fn select_method(methods: Vec<Method>) -> Result<MethodSelectionResponse, Method
 	}
 }
 
+/// On failure, returns the response for the client together with the cause.
 async fn perform_socks_request(
 	SocksRequest { command, address, port }: SocksRequest,
-) -> Result<(TcpStream, SocksResponse), SocksResponse> {
+	config: &ServerConfig,
+) -> Result<(TcpStream, SocksResponse), (SocksResponse, anyhow::Error)> {
 	if !matches!(command, Command::Connect) {
-		return Err(SocksResponse {
-			reply: SocksReply::CommandNotSupported,
-			address,
-			port,
-		});
+		return Err((
+			SocksResponse::failure(SocksReply::CommandNotSupported),
+			anyhow!("Command {command:?} not supported"),
+		));
 	}
 
-	let socket_addresses = match lookup_host(&address, port).await {
-		Ok(addresses) => addresses,
-		Err(reply) => return Err(SocksResponse { reply, address, port }),
-	};
-	let proxy_stream = match TcpStream::connect(socket_addresses.as_slice()).await {
-		Ok(stream) => {
-			info!(%address, port, "Upstream connection established");
-			stream
-		}
-		Err(error) => {
-			use ErrorKind::*;
-			let reply = match error.kind() {
-				PermissionDenied => SocksReply::ConnectionNotAllowedByRuleset,
-				ConnectionRefused => SocksReply::ConnectionRefused,
-				_ => SocksReply::GeneralSocksServerFailure,
-			};
-			// TODO: What port/address to use in error response
-			return Err(SocksResponse { reply, address, port });
-		}
-	};
+	Span::current().record("destination", format_args!("{}", AddressWithPort(&address, port)));
 
-	let bind_address = match proxy_stream.local_addr() {
-		Ok(address) => address,
-		Err(error) => {
-			error!("Error getting local address: {error}");
-			return Err(SocksResponse {
-				reply: SocksReply::GeneralSocksServerFailure,
-				address,
-				port,
-			});
+	let (proxy_stream, response) =
+		match tokio::time::timeout(config.connect_timeout, connect_upstream(&address, port, config)).await {
+			Ok(Ok((stream, response))) => {
+				if let Ok(upstream_address) = stream.peer_addr() {
+					Span::current().record("upstream", format_args!("{upstream_address}"));
+				}
+				info!(%address, port, "Upstream connection established");
+				(stream, response)
+			}
+			Ok(Err(reply)) => {
+				return Err((
+					SocksResponse::failure(reply),
+					anyhow!("Upstream connect failed with {reply:?}"),
+				))
+			}
+			Err(_) => {
+				return Err((
+					SocksResponse::failure(SocksReply::HostUnreachable),
+					anyhow!("Upstream connect timed out"),
+				))
+			}
+		};
+
+	if let Err(error) = configure_socket(&proxy_stream, config) {
+		return Err((
+			SocksResponse::failure(SocksReply::GeneralSocksServerFailure),
+			anyhow!("Error configuring upstream socket: {error}"),
+		));
+	}
+
+	Ok((proxy_stream, response))
+}
+
+fn configure_socket(stream: &TcpStream, config: &ServerConfig) -> std::io::Result<()> {
+	stream.set_nodelay(config.tcp_nodelay)?;
+	if let Some(keepalive) = config.tcp_keepalive {
+		let keepalive = TcpKeepalive::new().with_time(keepalive).with_interval(keepalive);
+		SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+	}
+	Ok(())
+}
+
+/// Returns the connection together with the success response for the client.
+async fn connect_upstream(
+	address: &Address,
+	port: u16,
+	config: &ServerConfig,
+) -> Result<(TcpStream, SocksResponse), SocksReply> {
+	if let Some(proxy_address) = config.upstream_proxy {
+		return upstream_proxy::connect(proxy_address, address, port, config.outgoing_address).await;
+	}
+
+	let mut socket_addresses = lookup_host(address, port).await?;
+	if let Some(outgoing_address) = config.outgoing_address {
+		socket_addresses.retain(|socket_address| is_same_address_family(outgoing_address, *socket_address));
+		if socket_addresses.is_empty() {
+			error!(%address, port, %outgoing_address, "No resolved address matches the address family of the outgoing address");
+			return Err(SocksReply::NetworkUnreachable);
 		}
-	};
+	}
+
+	let proxy_stream = happy_eyeballs::connect(
+		socket_addresses,
+		config.connection_attempt_delay,
+		config.outgoing_address,
+	)
+	.await
+	.map_err(|error| {
+		error!(%address, port, "Error connecting to destination: {error}");
+		reply_for_connect_error(&error)
+	})?;
+
+	let bind_address = proxy_stream.local_addr().map_err(|error| {
+		error!("Error getting local address: {error}");
+		SocksReply::GeneralSocksServerFailure
+	})?;
 
-	Ok((
-		proxy_stream,
-		SocksResponse {
-			reply: SocksReply::Succeeded,
-			// TODO: Is this the correct address to use in the response to CONNECT? I haven't fully understood the standard here.
-			// NOTE: OpenSSH seems to unconditionally return 0.0.0.0:0 here! https://github.com/openssh/openssh-portable/blob/800c2483e68db38bd1566ff69677124be974aceb/channels.c#L1512
-			address: bind_address.ip().into(),
-			port: bind_address.port(),
-		},
-	))
+	Ok((proxy_stream, SocksResponse::new(SocksReply::Succeeded, bind_address)))
 }
 
 async fn lookup_host(address: &Address, port: u16) -> Result<Vec<SocketAddr>, SocksReply> {
@@ -148,10 +333,485 @@ async fn lookup_host(address: &Address, port: u16) -> Result<Vec<SocketAddr>, So
 	})
 }
 
-async fn proxy_data(mut client_stream: TcpStream, mut server_stream: TcpStream) {
-	match tokio::io::copy_bidirectional(&mut client_stream, &mut server_stream).await {
-		Ok((request_bytes, response_bytes)) => info!(request_bytes, response_bytes, "Finished proxying"),
-		// FIXME: For some reason this always reports an error, even though the proxying works!
-		Err(error) => error!("Error proxying: {error}"),
+/// The byte counts are updated while proxying, so they are still correct if the session is cancelled or fails.
+async fn proxy_data(
+	mut client_stream: TcpStream,
+	mut server_stream: TcpStream,
+	connection_bandwidth_limit: Option<&BandwidthLimit>,
+	global_bandwidth_limit: Option<&BandwidthLimit>,
+	request_bytes: &mut u64,
+	response_bytes: &mut u64,
+) -> tokio::io::Result<()> {
+	let bandwidth_limits = [connection_bandwidth_limit, global_bandwidth_limit];
+	let upload_buckets: Vec<_> = bandwidth_limits.iter().flatten().map(|limit| &limit.upload).collect();
+	let download_buckets: Vec<_> = bandwidth_limits.iter().flatten().map(|limit| &limit.download).collect();
+
+	let (mut client_reader, mut client_writer) = client_stream.split();
+	let (mut server_reader, mut server_writer) = server_stream.split();
+	tokio::try_join!(
+		throttled_copy(&mut client_reader, &mut server_writer, &upload_buckets, request_bytes),
+		throttled_copy(
+			&mut server_reader,
+			&mut client_writer,
+			&download_buckets,
+			response_bytes
+		),
+	)?;
+	info!(
+		request_bytes = *request_bytes,
+		response_bytes = *response_bytes,
+		"Finished proxying"
+	);
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::access_log::AccessLogFormat;
+	use std::path::{Path, PathBuf};
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	/// The returned sender has to be kept alive, otherwise sessions are ended immediately.
+	fn test_config() -> (ServerConfig, watch::Sender<bool>) {
+		let (shutdown_sender, shutdown) = watch::channel(false);
+		let config = ServerConfig {
+			handshake_timeout: Duration::from_secs(10),
+			connect_timeout: Duration::from_secs(10),
+			connection_attempt_delay: Duration::from_millis(250),
+			upstream_proxy: None,
+			outgoing_address: None,
+			connection_rate_limit: 0,
+			global_bandwidth_limit: None,
+			connection_limit: None,
+			connection_limit_behavior: ConnectionLimitBehavior::Wait,
+			tcp_nodelay: true,
+			tcp_keepalive: None,
+			access_log: None,
+			shutdown,
+		};
+		(config, shutdown_sender)
+	}
+
+	/// Writes to a file in the temporary directory that is unique per test.
+	fn file_access_log(test_name: &str) -> (Arc<AccessLog>, PathBuf) {
+		let path = std::env::temp_dir().join(format!("minimal-socks5-{test_name}-{}.log", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		let access_log = AccessLog::open(AccessLogFormat::Text, Some(&path)).unwrap();
+		(Arc::new(access_log), path)
+	}
+
+	/// Waits for all records to be written and removes the file afterwards.
+	fn read_access_log(access_log: Arc<AccessLog>, path: &Path) -> String {
+		Arc::into_inner(access_log).expect("Access log is still in use").close();
+		let content = std::fs::read_to_string(path).unwrap();
+		let _ = std::fs::remove_file(path);
+		content
+	}
+
+	async fn select_no_authentication(address: SocketAddr) -> std::io::Result<[u8; 2]> {
+		let mut stream = TcpStream::connect(address).await?;
+		stream.write_all(&[0x05, 0x01, 0x00]).await?;
+		let mut response = [0u8; 2];
+		stream.read_exact(&mut response).await?;
+		Ok(response)
+	}
+
+	#[tokio::test]
+	async fn idle_listeners_dont_hold_connection_permits() {
+		let (mut config, _shutdown_sender) = test_config();
+		config.connection_limit = Some(Arc::new(Semaphore::new(1)));
+		let first_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let second_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let first_address = first_listener.local_addr().unwrap();
+		let second_address = second_listener.local_addr().unwrap();
+		let mut listeners = tokio::task::JoinSet::new();
+		listeners.spawn(listen_for_tcp_connections(first_listener, config.clone()));
+		listeners.spawn(listen_for_tcp_connections(second_listener, config));
+
+		for address in [second_address, first_address] {
+			let response = tokio::time::timeout(Duration::from_secs(5), select_no_authentication(address))
+				.await
+				.expect("Method selection timed out")
+				.unwrap();
+			assert_eq!(response, [0x05, 0x00]);
+		}
+	}
+
+	#[tokio::test]
+	async fn connection_limit_rejects_new_connections() {
+		let (access_log, access_log_path) = file_access_log("connection-limit-reject");
+		let (mut config, shutdown_sender) = test_config();
+		config.connection_limit = Some(Arc::new(Semaphore::new(1)));
+		config.connection_limit_behavior = ConnectionLimitBehavior::Reject;
+		config.access_log = Some(access_log.clone());
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		let listener_task = tokio::spawn(listen_for_tcp_connections(listener, config));
+
+		let mut first_stream = TcpStream::connect(address).await.unwrap();
+		first_stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+		let mut response = [0u8; 2];
+		first_stream.read_exact(&mut response).await.unwrap();
+		assert_eq!(response, [0x05, 0x00]);
+
+		let mut second_stream = TcpStream::connect(address).await.unwrap();
+		// Ignore errors here, the connection might already be closed
+		let _ = second_stream.write_all(&[0x05, 0x01, 0x00]).await;
+		let mut received = Vec::new();
+		let _ = tokio::time::timeout(Duration::from_secs(5), second_stream.read_to_end(&mut received))
+			.await
+			.expect("Rejected connection wasn't closed");
+		assert!(received.is_empty(), "{received:?}");
+
+		// End the first session and wait until nothing uses the access log anymore
+		listener_task.abort();
+		let _ = listener_task.await;
+		shutdown_sender.send(true).unwrap();
+		shutdown_sender.closed().await;
+		let access_log = read_access_log(access_log, &access_log_path);
+		assert!(
+			access_log.contains(r#" error="Connection limit reached" "#),
+			"{access_log}"
+		);
+	}
+
+	#[tokio::test]
+	async fn connection_limit_waits_for_running_connections() {
+		let (mut config, _shutdown_sender) = test_config();
+		config.connection_limit = Some(Arc::new(Semaphore::new(1)));
+		config.connection_limit_behavior = ConnectionLimitBehavior::Wait;
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		tokio::spawn(listen_for_tcp_connections(listener, config));
+
+		let mut first_stream = TcpStream::connect(address).await.unwrap();
+		first_stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+		let mut response = [0u8; 2];
+		first_stream.read_exact(&mut response).await.unwrap();
+		assert_eq!(response, [0x05, 0x00]);
+
+		let mut second_stream = TcpStream::connect(address).await.unwrap();
+		second_stream.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+		let mut response = [0u8; 2];
+		assert!(
+			tokio::time::timeout(Duration::from_millis(200), second_stream.read_exact(&mut response))
+				.await
+				.is_err(),
+			"Second connection was served while the first one was still running"
+		);
+
+		// Ends the first session because the SOCKS request is incomplete
+		drop(first_stream);
+		tokio::time::timeout(Duration::from_secs(5), second_stream.read_exact(&mut response))
+			.await
+			.expect("Second connection wasn't served after the first one ended")
+			.unwrap();
+		assert_eq!(response, [0x05, 0x00]);
+	}
+
+	#[tokio::test]
+	async fn upstream_connect_timeout_is_the_session_error() {
+		let silent_upstream_proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let (mut config, _shutdown_sender) = test_config();
+		config.upstream_proxy = Some(silent_upstream_proxy.local_addr().unwrap());
+		config.connect_timeout = Duration::from_millis(100);
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+
+		let client = async {
+			let mut stream = TcpStream::connect(address).await?;
+			stream.write_all(&[0x05, 0x01, 0x00]).await?;
+			stream
+				.write_all(&[0x05, 0x01, 0x00, 0x01, 192, 0, 2, 1, 0x01, 0xbb])
+				.await?;
+			let mut response = [0u8; 12];
+			stream.read_exact(&mut response).await?;
+			std::io::Result::Ok(response)
+		};
+		let server = async {
+			let (stream, client_address) = listener.accept().await?;
+			anyhow::Ok(run_socks_protocol(stream, client_address, config).await)
+		};
+		let (response, result) = tokio::join!(client, server);
+
+		// Method selection response followed by the SOCKS response with reply code X'04' Host unreachable
+		// and 0.0.0.0:0 as BND.ADDR and BND.PORT
+		assert_eq!(
+			response.unwrap(),
+			[0x05, 0x00, 0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0]
+		);
+		assert_eq!(result.unwrap().unwrap_err().to_string(), "Upstream connect timed out");
+	}
+
+	#[tokio::test]
+	async fn shutdown_during_proxying_logs_the_transferred_bytes() {
+		let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let upstream_port = upstream_listener.local_addr().unwrap().port();
+		let (access_log, access_log_path) = file_access_log("shutdown");
+		let (mut config, shutdown_sender) = test_config();
+		config.access_log = Some(access_log.clone());
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+
+		let upstream = async {
+			let (mut stream, _) = upstream_listener.accept().await?;
+			let mut request = [0u8; 100];
+			stream.read_exact(&mut request).await?;
+			stream.write_all(&[0x42; 1000]).await?;
+			// Keep the connection open until the proxy closes it
+			stream.read_to_end(&mut Vec::new()).await?;
+			std::io::Result::Ok(())
+		};
+		let client = async {
+			let mut stream = TcpStream::connect(address).await?;
+			stream.write_all(&[0x05, 0x01, 0x00]).await?;
+			let [port_high, port_low] = upstream_port.to_be_bytes();
+			stream
+				.write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, port_high, port_low])
+				.await?;
+			let mut handshake_response = [0u8; 12];
+			stream.read_exact(&mut handshake_response).await?;
+			stream.write_all(&[0x23; 100]).await?;
+			let mut response = [0u8; 1000];
+			stream.read_exact(&mut response).await?;
+
+			// End the session while both directions are still open
+			shutdown_sender.send(true).unwrap();
+			std::io::Result::Ok(())
+		};
+		let server = async {
+			let (stream, client_address) = listener.accept().await?;
+			anyhow::Ok(run_socks_protocol(stream, client_address, config).await)
+		};
+		let (upstream_result, client_result, server_result) = tokio::join!(upstream, client, server);
+		upstream_result.unwrap();
+		client_result.unwrap();
+		assert_eq!(server_result.unwrap().unwrap_err().to_string(), "Server shut down");
+
+		let access_log = read_access_log(access_log, &access_log_path);
+		assert!(
+			access_log.contains(r#"error="Server shut down" request_bytes=100 response_bytes=1000 "#),
+			"{access_log}"
+		);
+	}
+
+	#[tokio::test]
+	async fn upstream_reset_is_the_session_error() {
+		let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let upstream_port = upstream_listener.local_addr().unwrap().port();
+		let (access_log, access_log_path) = file_access_log("upstream-reset");
+		let (mut config, _shutdown_sender) = test_config();
+		config.access_log = Some(access_log.clone());
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+
+		let upstream = async {
+			let (mut stream, _) = upstream_listener.accept().await?;
+			let mut request = [0u8; 100];
+			stream.read_exact(&mut request).await?;
+			// Closing with a linger time of 0 sends a RST instead of a FIN
+			SockRef::from(&stream).set_linger(Some(Duration::ZERO))?;
+			std::io::Result::Ok(())
+		};
+		let client = async {
+			let mut stream = TcpStream::connect(address).await?;
+			stream.write_all(&[0x05, 0x01, 0x00]).await?;
+			let [port_high, port_low] = upstream_port.to_be_bytes();
+			stream
+				.write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, port_high, port_low])
+				.await?;
+			let mut handshake_response = [0u8; 12];
+			stream.read_exact(&mut handshake_response).await?;
+			stream.write_all(&[0x23; 100]).await?;
+			// Keep the connection open until the proxy closes it
+			stream.read_to_end(&mut Vec::new()).await?;
+			std::io::Result::Ok(())
+		};
+		let server = async {
+			let (stream, client_address) = listener.accept().await?;
+			anyhow::Ok(run_socks_protocol(stream, client_address, config).await)
+		};
+		let (upstream_result, client_result, server_result) = tokio::join!(upstream, client, server);
+		upstream_result.unwrap();
+		client_result.unwrap();
+		let error = server_result.unwrap().unwrap_err().to_string();
+		assert!(error.starts_with("Error proxying: "), "{error}");
+
+		let access_log = read_access_log(access_log, &access_log_path);
+		assert!(
+			access_log.contains(&format!(
+				r#" reply=0 error="{error}" request_bytes=100 response_bytes=0 "#
+			)),
+			"{access_log}"
+		);
+	}
+
+	#[tokio::test]
+	async fn outgoing_address_of_other_family_is_unreachable() {
+		let (mut config, _shutdown_sender) = test_config();
+		config.outgoing_address = Some(IpAddr::from([127, 0, 0, 1]));
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+
+		let client = async {
+			let mut stream = TcpStream::connect(address).await?;
+			stream.write_all(&[0x05, 0x01, 0x00]).await?;
+			let mut request = vec![0x05, 0x01, 0x00, 0x04];
+			request.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+			request.extend_from_slice(&443u16.to_be_bytes());
+			stream.write_all(&request).await?;
+			let mut response = [0u8; 12];
+			stream.read_exact(&mut response).await?;
+			std::io::Result::Ok(response)
+		};
+		let server = async {
+			let (stream, client_address) = listener.accept().await?;
+			anyhow::Ok(run_socks_protocol(stream, client_address, config).await)
+		};
+		let (response, result) = tokio::join!(client, server);
+
+		// Method selection response followed by the SOCKS response with reply code X'03' Network unreachable
+		// and 0.0.0.0:0 as BND.ADDR and BND.PORT
+		assert_eq!(
+			response.unwrap(),
+			[0x05, 0x00, 0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0]
+		);
+		assert_eq!(
+			result.unwrap().unwrap_err().to_string(),
+			"Upstream connect failed with NetworkUnreachable"
+		);
+	}
+
+	#[tokio::test]
+	async fn outgoing_address_is_the_bind_address() {
+		let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let upstream_port = upstream_listener.local_addr().unwrap().port();
+		let (mut config, _shutdown_sender) = test_config();
+		config.outgoing_address = Some(IpAddr::from([127, 0, 0, 1]));
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+
+		let upstream = async {
+			let (stream, peer_address) = upstream_listener.accept().await?;
+			std::io::Result::Ok((stream, peer_address))
+		};
+		let client = async {
+			let mut stream = TcpStream::connect(address).await?;
+			stream.write_all(&[0x05, 0x01, 0x00]).await?;
+			let [port_high, port_low] = upstream_port.to_be_bytes();
+			stream
+				.write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, port_high, port_low])
+				.await?;
+			let mut response = [0u8; 12];
+			stream.read_exact(&mut response).await?;
+			std::io::Result::Ok((stream, response))
+		};
+		let server = async {
+			let (stream, client_address) = listener.accept().await?;
+			anyhow::Ok(run_socks_protocol(stream, client_address, config).await)
+		};
+		// The session keeps running as long as both connections are open
+		let ((_upstream_stream, peer_address), (_client_stream, response)) = tokio::select! {
+			result = async { tokio::try_join!(upstream, client) } => result.unwrap(),
+			result = server => panic!("Session ended early: {result:?}"),
+		};
+
+		let [port_high, port_low] = peer_address.port().to_be_bytes();
+		assert_eq!(peer_address.ip(), IpAddr::from([127, 0, 0, 1]));
+		// Method selection response followed by the SOCKS response with reply code X'00' Succeeded
+		// and the address the upstream connection originates from as BND.ADDR and BND.PORT
+		assert_eq!(
+			response,
+			[0x05, 0x00, 0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, port_high, port_low]
+		);
+	}
+
+	/// Sends a CONNECT to example.com:443 through a fake upstream proxy that answers with `upstream_response`,
+	/// returns what the client receives after the method selection response.
+	async fn connect_through_fake_upstream_proxy(upstream_response: [u8; 10]) -> [u8; 10] {
+		let upstream_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let (mut config, _shutdown_sender) = test_config();
+		config.upstream_proxy = Some(upstream_listener.local_addr().unwrap());
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let address = listener.local_addr().unwrap();
+		let connect_request = [&[0x05, 0x01, 0x00, 0x03, 11][..], b"example.com", &443u16.to_be_bytes()].concat();
+
+		tokio::spawn(async move {
+			let (stream, client_address) = listener.accept().await.unwrap();
+			run_socks_protocol(stream, client_address, config).await
+		});
+		let upstream = async {
+			let (mut stream, _) = upstream_listener.accept().await?;
+			let mut method_selection_request = [0u8; 3];
+			stream.read_exact(&mut method_selection_request).await?;
+			assert_eq!(method_selection_request, [0x05, 0x01, 0x00]);
+			stream.write_all(&[0x05, 0x00]).await?;
+
+			// The domain name is passed on without resolving it
+			let mut request = vec![0u8; connect_request.len()];
+			stream.read_exact(&mut request).await?;
+			assert_eq!(request, connect_request);
+			stream.write_all(&upstream_response).await?;
+			std::io::Result::Ok(stream)
+		};
+		let client = async {
+			let mut stream = TcpStream::connect(address).await?;
+			stream.write_all(&[0x05, 0x01, 0x00]).await?;
+			let mut method_selection_response = [0u8; 2];
+			stream.read_exact(&mut method_selection_response).await?;
+			assert_eq!(method_selection_response, [0x05, 0x00]);
+			stream.write_all(&connect_request).await?;
+			let mut response = [0u8; 10];
+			stream.read_exact(&mut response).await?;
+			std::io::Result::Ok(response)
+		};
+		let (_upstream_stream, response) =
+			tokio::time::timeout(Duration::from_secs(5), async { tokio::try_join!(upstream, client) })
+				.await
+				.expect("Connecting through the upstream proxy timed out")
+				.unwrap();
+		response
+	}
+
+	#[tokio::test]
+	async fn upstream_proxy_bind_address_is_passed_on() {
+		let response = connect_through_fake_upstream_proxy([0x05, 0x00, 0x00, 0x01, 192, 0, 2, 1, 0x12, 0x34]).await;
+		assert_eq!(response, [0x05, 0x00, 0x00, 0x01, 192, 0, 2, 1, 0x12, 0x34]);
+	}
+
+	#[tokio::test]
+	async fn upstream_proxy_failure_reply_is_passed_on() {
+		// X'05' Connection refused
+		let response = connect_through_fake_upstream_proxy([0x05, 0x05, 0x00, 0x01, 192, 0, 2, 1, 0x12, 0x34]).await;
+		assert_eq!(response, [0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+	}
+
+	#[tokio::test]
+	async fn upstream_proxy_unassigned_reply_is_general_failure() {
+		// X'09' is unassigned, so it is passed on as X'01' General SOCKS server failure
+		let response = connect_through_fake_upstream_proxy([0x05, 0x09, 0x00, 0x01, 192, 0, 2, 1, 0x12, 0x34]).await;
+		assert_eq!(response, [0x05, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+	}
+
+	#[tokio::test]
+	async fn configure_socket_works_for_ipv4_and_ipv6() {
+		for listen_address in ["127.0.0.1:0", "[::1]:0"] {
+			let listener = TcpListener::bind(listen_address).await.unwrap();
+			let (_client_stream, accepted) =
+				tokio::try_join!(TcpStream::connect(listener.local_addr().unwrap()), listener.accept()).unwrap();
+			let (stream, _) = accepted;
+
+			let (mut config, _shutdown_sender) = test_config();
+			config.tcp_nodelay = true;
+			config.tcp_keepalive = Some(Duration::from_secs(30));
+			configure_socket(&stream, &config).unwrap();
+			assert!(stream.nodelay().unwrap(), "{listen_address}");
+			assert!(SockRef::from(&stream).keepalive().unwrap(), "{listen_address}");
+
+			config.tcp_nodelay = false;
+			configure_socket(&stream, &config).unwrap();
+			assert!(!stream.nodelay().unwrap(), "{listen_address}");
+		}
 	}
 }
@@ -0,0 +1,119 @@
+//! systemd socket activation, see https://www.freedesktop.org/software/systemd/man/latest/sd_listen_fds.html
+
+use anyhow::{bail, Context};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::env;
+use std::os::fd::{BorrowedFd, FromRawFd, RawFd};
+use tokio::net::TcpListener;
+use tracing::debug;
+
+/// > #define SD_LISTEN_FDS_START 3
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the listening sockets passed in by systemd, or `None` if the process wasn't socket activated.
+pub fn inherited_listeners() -> anyhow::Result<Option<Vec<TcpListener>>> {
+	let Some(listen_pid) = env::var_os("LISTEN_PID") else {
+		return Ok(None);
+	};
+	let listen_pid = listen_pid
+		.to_str()
+		.and_then(|pid| pid.parse::<u32>().ok())
+		.context("Invalid LISTEN_PID")?;
+	if listen_pid != std::process::id() {
+		debug!(
+			listen_pid,
+			"LISTEN_PID doesn't match, ignoring inherited file descriptors"
+		);
+		return Ok(None);
+	}
+
+	let fd_count = env::var("LISTEN_FDS")
+		.context("LISTEN_PID is set but LISTEN_FDS is missing")?
+		.parse::<RawFd>()
+		.context("Invalid LISTEN_FDS")?;
+	if fd_count < 0 {
+		bail!("LISTEN_FDS is {fd_count}, expected a non-negative number of file descriptors");
+	}
+	if fd_count == 0 {
+		debug!("LISTEN_FDS is 0, no sockets were passed in");
+		return Ok(None);
+	}
+
+	if let Ok(fd_names) = env::var("LISTEN_FDNAMES") {
+		let name_count = fd_names.split(':').count();
+		if usize::try_from(fd_count).ok() != Some(name_count) {
+			bail!("LISTEN_FDS is {fd_count} but LISTEN_FDNAMES contains {name_count} names");
+		}
+	}
+
+	let fd_end = SD_LISTEN_FDS_START
+		.checked_add(fd_count)
+		.with_context(|| format!("LISTEN_FDS is {fd_count}, which exceeds the range of file descriptors"))?;
+	// Check every file descriptor before taking ownership, otherwise an invalid one would get closed even though
+	// it might belong to something else in this process, e.g. when LISTEN_FDS is larger than the number of sockets.
+	let fds = SD_LISTEN_FDS_START..fd_end;
+	for fd in fds.clone() {
+		check_listening_socket(fd).with_context(|| format!("Invalid inherited file descriptor {fd}"))?;
+	}
+	fds.map(|fd| listener_from_fd(fd).with_context(|| format!("Invalid inherited file descriptor {fd}")))
+		.collect::<anyhow::Result<_>>()
+		.map(Some)
+}
+
+fn check_listening_socket(fd: RawFd) -> anyhow::Result<()> {
+	// SAFETY: The file descriptor is only borrowed and never closed. If it isn't open,
+	// the system calls below fail with EBADF.
+	let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+	let socket = SockRef::from(&fd);
+
+	if !matches!(socket.domain()?, Domain::IPV4 | Domain::IPV6)
+		|| socket.r#type()? != Type::STREAM
+		|| socket.protocol()? != Some(Protocol::TCP)
+	{
+		bail!("Not a TCP socket");
+	}
+	if !socket.is_listener()? {
+		bail!("Socket is not listening");
+	}
+	Ok(())
+}
+
+/// Must only be called once `fd` has passed [`check_listening_socket`].
+fn listener_from_fd(fd: RawFd) -> anyhow::Result<TcpListener> {
+	// SAFETY: systemd passes ownership of the file descriptors starting at SD_LISTEN_FDS_START to this process,
+	// each of them has been checked to be a listening socket and is only converted once.
+	let socket = unsafe { Socket::from_raw_fd(fd) };
+	socket.set_cloexec(true)?;
+
+	let listener = std::net::TcpListener::from(socket);
+	listener.set_nonblocking(true)?;
+	Ok(TcpListener::from_std(listener)?)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::{SocketAddr, UdpSocket};
+	use std::os::fd::AsRawFd;
+
+	#[test]
+	fn listening_tcp_socket_is_accepted() {
+		let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+		check_listening_socket(listener.as_raw_fd()).unwrap();
+	}
+
+	#[test]
+	fn tcp_socket_that_isnt_listening_is_rejected() {
+		let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).unwrap();
+		socket.bind(&SocketAddr::from(([127, 0, 0, 1], 0)).into()).unwrap();
+		let error = check_listening_socket(socket.as_raw_fd()).unwrap_err();
+		assert_eq!(error.to_string(), "Socket is not listening");
+	}
+
+	#[test]
+	fn udp_socket_is_rejected() {
+		let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+		let error = check_listening_socket(socket.as_raw_fd()).unwrap_err();
+		assert_eq!(error.to_string(), "Not a TCP socket");
+	}
+}
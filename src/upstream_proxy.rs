@@ -0,0 +1,68 @@
+//! Client side of the SOCKS5 protocol, used for chaining through an upstream SOCKS5 proxy.
+
+use crate::message::{
+	Address, Command, Method, MethodSelectionRequest, MethodSelectionResponse, SocksReply, SocksRequest, SocksResponse,
+};
+use crate::outgoing::{connect_from, is_same_address_family, reply_for_connect_error};
+use anyhow::bail;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::TcpStream;
+use tracing::error;
+
+/// Connects to the upstream proxy and asks it to CONNECT to the destination.
+///
+/// The destination is forwarded as-is, so domain names are resolved by the upstream proxy.
+/// On success, the upstream's response is returned so its BND.ADDR and BND.PORT can be passed on to the client.
+pub async fn connect(
+	proxy_address: SocketAddr,
+	address: &Address,
+	port: u16,
+	outgoing_address: Option<IpAddr>,
+) -> Result<(TcpStream, SocksResponse), SocksReply> {
+	if let Some(outgoing_address) = outgoing_address {
+		if !is_same_address_family(outgoing_address, proxy_address) {
+			error!(%proxy_address, %outgoing_address, "Upstream proxy doesn't match the address family of the outgoing address");
+			return Err(SocksReply::NetworkUnreachable);
+		}
+	}
+
+	let mut proxy_stream = connect_from(proxy_address, outgoing_address).await.map_err(|error| {
+		error!(%proxy_address, "Error connecting to upstream proxy: {error}");
+		reply_for_connect_error(&error)
+	})?;
+
+	let socks_request = SocksRequest {
+		command: Command::Connect,
+		address: address.clone(),
+		port,
+	};
+	match handshake(&mut proxy_stream, &socks_request).await {
+		Ok(response) if matches!(response.reply, SocksReply::Succeeded) => Ok((proxy_stream, response)),
+		Ok(SocksResponse { reply, .. }) => {
+			error!(%proxy_address, %address, port, "Upstream proxy replied with {reply:?}");
+			match reply {
+				SocksReply::Unassigned(_) => Err(SocksReply::GeneralSocksServerFailure),
+				reply => Err(reply),
+			}
+		}
+		Err(error) => {
+			error!(%proxy_address, "Handshake with upstream proxy failed: {error}");
+			Err(SocksReply::GeneralSocksServerFailure)
+		}
+	}
+}
+
+async fn handshake(proxy_stream: &mut TcpStream, socks_request: &SocksRequest) -> anyhow::Result<SocksResponse> {
+	MethodSelectionRequest {
+		methods: vec![Method::NoAuthenticationRequired],
+	}
+	.write_to_stream(proxy_stream)
+	.await?;
+	let MethodSelectionResponse { method } = MethodSelectionResponse::parse_from_stream(proxy_stream).await?;
+	if method != Method::NoAuthenticationRequired {
+		bail!("Upstream proxy selected unsupported method {method:?}");
+	}
+
+	socks_request.write_to_stream(proxy_stream).await?;
+	Ok(SocksResponse::parse_from_stream(proxy_stream).await?)
+}